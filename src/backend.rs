@@ -0,0 +1,140 @@
+//! A narrow trait covering the calendar operations `Sync` needs, so a sync
+//! can target either a Google Calendar or a CalDAV collection on either end.
+
+use async_trait::async_trait;
+use eyre::Result;
+use futures::TryStreamExt;
+use google_apis_common::Connector;
+use google_calendar3::api::Event;
+use google_calendar3::CalendarHub;
+
+use crate::SRC_ID_KEY;
+
+#[derive(Debug, Clone, Default)]
+pub struct ListOptions {
+    pub updated_after: Option<chrono::DateTime<chrono::Utc>>,
+    pub time_min: Option<chrono::DateTime<chrono::Utc>>,
+    /// A previously-persisted incremental-listing token, for backends that
+    /// support one (currently just Google's `nextSyncToken`). Backends that
+    /// don't support incremental listing ignore this and always list in full.
+    pub sync_token: Option<String>,
+}
+
+/// The minimal set of operations the `Sync` (and so `Watch`) command needs
+/// from a calendar, abstracted over backend so it can mix-and-match Google
+/// and CalDAV on either side of a sync, and an ICS feed as its source.
+#[async_trait]
+pub trait CalendarBackend: Send + Sync {
+    /// Lists events on `calendar`, returning the events, the token to persist
+    /// for next time's incremental listing (`None` if this backend doesn't
+    /// support one), and whether this was a full (as opposed to incremental
+    /// or `updated_after`-filtered) listing — only a full listing can be
+    /// used to detect deletions by diffing against the destination mirror,
+    /// since an incremental listing omits anything that didn't change.
+    async fn list_events(&self, calendar: &str, opts: &ListOptions) -> Result<(Vec<Event>, Option<String>, bool)>;
+    async fn insert_event(&self, calendar: &str, event: Event) -> Result<Event>;
+    async fn patch_event(&self, calendar: &str, event_id: &str, event: Event) -> Result<Event>;
+    async fn delete_event(&self, calendar: &str, event_id: &str) -> Result<()>;
+    async fn list_calendars(&self) -> Result<Vec<String>>;
+
+    /// Returns the cross-backend id a mirrored `event` was copied from, if
+    /// any. Google destinations stash it in the `SRC_ID_KEY` extended
+    /// property (the only place a third-party value survives a round trip
+    /// through the API); CalDAV destinations just reuse the iCal `UID`
+    /// (`Event::id`) directly, since CalDAV has no equivalent to extended
+    /// properties and the UID is already the natural cross-backend identity.
+    fn src_id_of(&self, event: &Event) -> Option<String>;
+}
+
+pub struct GoogleBackend<C> {
+    hub: CalendarHub<C>,
+}
+
+impl<C> GoogleBackend<C> {
+    pub fn new(hub: CalendarHub<C>) -> Self {
+        Self { hub }
+    }
+}
+
+#[async_trait]
+impl<C> CalendarBackend for GoogleBackend<C>
+where
+    C: Connector + 'static,
+{
+    async fn list_events(&self, calendar: &str, opts: &ListOptions) -> Result<(Vec<Event>, Option<String>, bool)> {
+        let sync_token = opts.sync_token.clone();
+        // An `updated_after` filter omits unchanged events just like a sync
+        // token does, so such a listing can't be diffed for deletions either.
+        let unfiltered = opts.updated_after.is_none();
+        let (stream, handle) =
+            crate::list_events_with_token(&self.hub, calendar, opts.updated_after, opts.time_min, sync_token.clone());
+        match stream.try_collect::<Vec<Event>>().await {
+            Ok(events) => Ok((events, handle.get(), sync_token.is_none() && unfiltered)),
+            Err(err) if sync_token.is_some() && crate::is_sync_token_expired(&err) => {
+                log::warn!("Sync token for {} expired, falling back to a full resync", calendar);
+                let (stream, handle) =
+                    crate::list_events_with_token(&self.hub, calendar, opts.updated_after, opts.time_min, None);
+                let events = stream.try_collect::<Vec<Event>>().await?;
+                Ok((events, handle.get(), unfiltered))
+            }
+            Err(err) => Err(err),
+        }
+    }
+
+    async fn insert_event(&self, calendar: &str, event: Event) -> Result<Event> {
+        let (_, event) = self
+            .hub
+            .events()
+            .insert(event, calendar)
+            .add_scope(google_calendar3::api::Scope::Event)
+            .doit()
+            .await?;
+        Ok(event)
+    }
+
+    async fn patch_event(&self, calendar: &str, event_id: &str, event: Event) -> Result<Event> {
+        let (_, event) = self
+            .hub
+            .events()
+            .patch(event, calendar, event_id)
+            .add_scope(google_calendar3::api::Scope::Event)
+            .doit()
+            .await?;
+        Ok(event)
+    }
+
+    async fn delete_event(&self, calendar: &str, event_id: &str) -> Result<()> {
+        self.hub
+            .events()
+            .delete(calendar, event_id)
+            .add_scope(google_calendar3::api::Scope::Event)
+            .doit()
+            .await?;
+        Ok(())
+    }
+
+    async fn list_calendars(&self) -> Result<Vec<String>> {
+        let hub = &self.hub;
+        let iter = crate::page_iterator::stream_items(
+            move |next_page_token| async move {
+                let req = hub.calendar_list().list();
+                let req = match next_page_token {
+                    Some(token) => req.page_token(token.as_str()),
+                    None => req,
+                };
+                let (_body, response) = req.doit().await?;
+                Ok::<_, eyre::Report>((response.items.unwrap_or(vec![]), response.next_page_token))
+            },
+            |items| items,
+        );
+        iter.try_filter_map(|cal| async move { Ok(cal.id) }).try_collect().await
+    }
+
+    fn src_id_of(&self, event: &Event) -> Option<String> {
+        event
+            .extended_properties
+            .as_ref()
+            .and_then(|x| x.shared.as_ref())
+            .and_then(|m| m.get(SRC_ID_KEY).cloned())
+    }
+}
@@ -0,0 +1,308 @@
+//! A [`CalendarBackend`] for CalDAV collections (Nextcloud, Radicale,
+//! aerogramme, ...), speaking just enough WebDAV to list, fetch, and write
+//! `.ics` resources in a single collection.
+
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
+use eyre::{bail, eyre, Result, WrapErr};
+use google_apis_common::Connector;
+use google_calendar3::api::{Event, EventDateTime};
+use http_body_util::{BodyExt, Full};
+use hyper_util::client::legacy::Client;
+
+use crate::backend::{CalendarBackend, ListOptions};
+use crate::ics;
+
+/// A `--src`/`--dst` spec of the form `caldav+http(s)://[user[:pass]@]host/path/to/collection/`.
+#[derive(Debug, Clone)]
+pub struct CalDavConfig {
+    pub collection_url: String,
+    pub username: Option<String>,
+    pub password: Option<String>,
+}
+
+/// Parses `spec` as a CalDAV target, returning `None` for anything that
+/// doesn't start with the `caldav+` prefix (i.e. a Google calendar id or an
+/// ICS feed URL).
+pub fn parse_caldav_target(spec: &str) -> Option<CalDavConfig> {
+    let (scheme, rest) = if let Some(rest) = spec.strip_prefix("caldav+http://") {
+        ("http", rest)
+    } else if let Some(rest) = spec.strip_prefix("caldav+https://") {
+        ("https", rest)
+    } else {
+        return None;
+    };
+
+    let (userinfo, host_and_path) = match rest.split_once('@') {
+        Some((userinfo, rest)) => (Some(userinfo), rest),
+        None => (None, rest),
+    };
+    let (username, password) = match userinfo {
+        Some(userinfo) => match userinfo.split_once(':') {
+            Some((u, p)) => (Some(u.to_string()), Some(p.to_string())),
+            None => (Some(userinfo.to_string()), None),
+        },
+        None => (None, None),
+    };
+
+    Some(CalDavConfig {
+        collection_url: format!("{}://{}", scheme, host_and_path.trim_end_matches('/')),
+        username,
+        password,
+    })
+}
+
+const BASE64_CHARS: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+/// A tiny standard-alphabet base64 encoder, just for building a `Basic` auth
+/// header without pulling in a dedicated dependency.
+fn base64_encode(input: &[u8]) -> String {
+    let mut out = String::with_capacity((input.len() + 2) / 3 * 4);
+    for chunk in input.chunks(3) {
+        let b0 = chunk[0];
+        let b1 = *chunk.get(1).unwrap_or(&0);
+        let b2 = *chunk.get(2).unwrap_or(&0);
+        let n = ((b0 as u32) << 16) | ((b1 as u32) << 8) | (b2 as u32);
+        out.push(BASE64_CHARS[((n >> 18) & 0x3F) as usize] as char);
+        out.push(BASE64_CHARS[((n >> 12) & 0x3F) as usize] as char);
+        out.push(if chunk.len() > 1 { BASE64_CHARS[((n >> 6) & 0x3F) as usize] as char } else { '=' });
+        out.push(if chunk.len() > 2 { BASE64_CHARS[(n & 0x3F) as usize] as char } else { '=' });
+    }
+    out
+}
+
+/// Percent-encodes everything except RFC 3986 unreserved characters, so a
+/// `uid` containing `/`, `..`, or other path-significant characters (e.g.
+/// from an untrusted upstream ICS feed's `UID`) becomes a single opaque path
+/// segment instead of being able to break out of the collection's path when
+/// interpolated into a resource URL.
+fn percent_encode_path_segment(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for byte in s.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => out.push(byte as char),
+            _ => out.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    out
+}
+
+pub struct CalDavBackend<C> {
+    client: Client<C, Full<Bytes>>,
+    config: CalDavConfig,
+}
+
+impl<C> CalDavBackend<C>
+where
+    C: Connector,
+{
+    pub fn new(client: Client<C, Full<Bytes>>, config: CalDavConfig) -> Self {
+        Self { client, config }
+    }
+
+    fn resource_url(&self, uid: &str) -> String {
+        format!("{}/{}.ics", self.config.collection_url, percent_encode_path_segment(uid))
+    }
+
+    fn authorize(&self, mut builder: http::request::Builder) -> http::request::Builder {
+        if let Some(username) = &self.config.username {
+            let password = self.config.password.as_deref().unwrap_or("");
+            let encoded = base64_encode(format!("{}:{}", username, password).as_bytes());
+            builder = builder.header(http::header::AUTHORIZATION, format!("Basic {}", encoded));
+        }
+        builder
+    }
+
+    async fn request(
+        &self, method: &str, url: &str, body: String, headers: &[(&str, &str)],
+    ) -> Result<(http::StatusCode, String)> {
+        let mut builder = http::Request::builder().method(method).uri(url);
+        for (name, value) in headers {
+            builder = builder.header(*name, *value);
+        }
+        let builder = self.authorize(builder);
+        let req = builder.body(Full::new(Bytes::from(body))).wrap_err("Failed to build CalDAV request")?;
+
+        let response = self
+            .client
+            .request(req)
+            .await
+            .wrap_err(format!("CalDAV {} {} failed", method, url))?;
+        let status = response.status();
+        let bytes = response
+            .into_body()
+            .collect()
+            .await
+            .wrap_err(format!("Failed to read response body for {} {}", method, url))?
+            .to_bytes();
+        Ok((status, String::from_utf8_lossy(&bytes).into_owned()))
+    }
+
+    /// Pulls every `href` out of a DAV multistatus XML response. A hand-rolled
+    /// scan instead of a real XML parser: the only thing we need out of a
+    /// PROPFIND/REPORT response is the list of member resource paths.
+    fn extract_hrefs(xml: &str) -> Vec<String> {
+        let mut hrefs = Vec::new();
+        let mut rest = xml;
+        while let Some(tag_start) = rest.find("href>") {
+            let after = &rest[tag_start + "href>".len()..];
+            match after.find("</") {
+                Some(tag_end) => {
+                    hrefs.push(after[..tag_end].trim().to_string());
+                    // Skip past the closing tag, whose own `href>` would
+                    // otherwise be taken for the next opening tag.
+                    let closing = &after[tag_end..];
+                    rest = match closing.find('>') {
+                        Some(gt) => &closing[gt + 1..],
+                        None => break,
+                    };
+                }
+                None => break,
+            }
+        }
+        hrefs
+    }
+
+    fn resolve_href(&self, href: &str) -> String {
+        if href.starts_with("http://") || href.starts_with("https://") {
+            return href.to_string();
+        }
+        let origin = self.config.collection_url.split("://").nth(1).and_then(|rest| rest.split_once('/')).map(|(host, _)| host);
+        let scheme = self.config.collection_url.split("://").next().unwrap_or("https");
+        match origin {
+            Some(host) => format!("{}://{}{}", scheme, host, href),
+            None => format!("{}{}", self.config.collection_url, href),
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl<C> CalendarBackend for CalDavBackend<C>
+where
+    C: Connector,
+{
+    async fn list_events(&self, _calendar: &str, _opts: &ListOptions) -> Result<(Vec<Event>, Option<String>, bool)> {
+        let (status, body) = self
+            .request(
+                "PROPFIND", &self.config.collection_url, String::new(),
+                &[("Depth", "1"), ("Content-Type", "application/xml; charset=utf-8")],
+            )
+            .await?;
+        if !status.is_success() {
+            bail!("PROPFIND on {} failed: {}", self.config.collection_url, status);
+        }
+
+        let mut events = Vec::new();
+        for href in Self::extract_hrefs(&body) {
+            if !href.ends_with(".ics") {
+                continue;
+            }
+            let resource_url = self.resolve_href(&href);
+            let (status, ics_body) = self.request("GET", &resource_url, String::new(), &[]).await?;
+            if !status.is_success() {
+                log::warn!("Failed to GET {}: {}", resource_url, status);
+                continue;
+            }
+            events.extend(ics::parse_vevents(&ics_body)?);
+        }
+        Ok((events, None, true))
+    }
+
+    async fn insert_event(&self, _calendar: &str, event: Event) -> Result<Event> {
+        let uid = event.id.clone().ok_or(eyre!("Event missing id/UID"))?;
+        let url = self.resource_url(&uid);
+        let body = render_vevent(&event)?;
+        let (status, _) = self
+            .request("PUT", &url, body, &[("Content-Type", "text/calendar; charset=utf-8"), ("If-None-Match", "*")])
+            .await?;
+        if !status.is_success() {
+            bail!("PUT {} failed: {}", url, status);
+        }
+        Ok(event)
+    }
+
+    async fn patch_event(&self, _calendar: &str, event_id: &str, event: Event) -> Result<Event> {
+        let url = self.resource_url(event_id);
+        let body = render_vevent(&event)?;
+        let (status, _) = self.request("PUT", &url, body, &[("Content-Type", "text/calendar; charset=utf-8")]).await?;
+        if !status.is_success() {
+            bail!("PUT {} failed: {}", url, status);
+        }
+        Ok(event)
+    }
+
+    async fn delete_event(&self, _calendar: &str, event_id: &str) -> Result<()> {
+        let url = self.resource_url(event_id);
+        let (status, _) = self.request("DELETE", &url, String::new(), &[]).await?;
+        if !status.is_success() && status != http::StatusCode::NOT_FOUND {
+            bail!("DELETE {} failed: {}", url, status);
+        }
+        Ok(())
+    }
+
+    async fn list_calendars(&self) -> Result<Vec<String>> {
+        let (status, body) = self
+            .request(
+                "PROPFIND", &self.config.collection_url, String::new(),
+                &[("Depth", "1"), ("Content-Type", "application/xml; charset=utf-8")],
+            )
+            .await?;
+        if !status.is_success() {
+            bail!("PROPFIND on {} failed: {}", self.config.collection_url, status);
+        }
+        Ok(Self::extract_hrefs(&body))
+    }
+
+    fn src_id_of(&self, event: &Event) -> Option<String> {
+        event.id.clone()
+    }
+}
+
+fn ics_escape(s: &str) -> String {
+    s.replace('\\', "\\\\").replace(',', "\\,").replace(';', "\\;").replace('\n', "\\n")
+}
+
+fn render_event_date_time(name: &str, edt: &EventDateTime) -> Result<String> {
+    if let Some(date_time) = edt.date_time {
+        Ok(format!("{}:{}", name, format_date_time(date_time)))
+    } else if let Some(date) = edt.date {
+        Ok(format!("{};VALUE=DATE:{}", name, date.format("%Y%m%d")))
+    } else {
+        bail!("EventDateTime has neither date_time nor date")
+    }
+}
+
+fn format_date_time(dt: DateTime<Utc>) -> String {
+    dt.format("%Y%m%dT%H%M%SZ").to_string()
+}
+
+/// Renders the fields `Sync` mirrors as a minimal `VCALENDAR`/`VEVENT`
+/// document, suitable for `PUT`ing to a CalDAV resource. The inverse of
+/// `ics::parse_vevents`.
+fn render_vevent(event: &Event) -> Result<String> {
+    let uid = event.id.clone().ok_or(eyre!("Event missing id/UID"))?;
+    let mut lines = vec!["BEGIN:VCALENDAR".to_string(), "VERSION:2.0".to_string(), "BEGIN:VEVENT".to_string(), format!("UID:{}", uid)];
+
+    if let Some(summary) = &event.summary {
+        lines.push(format!("SUMMARY:{}", ics_escape(summary)));
+    }
+    if let Some(location) = &event.location {
+        lines.push(format!("LOCATION:{}", ics_escape(location)));
+    }
+    if let Some(start) = &event.start {
+        lines.push(render_event_date_time("DTSTART", start)?);
+    }
+    if let Some(end) = &event.end {
+        lines.push(render_event_date_time("DTEND", end)?);
+    }
+    for rule in event.recurrence.iter().flatten() {
+        lines.push(rule.clone());
+    }
+    if let Some(color_id) = &event.color_id {
+        lines.push(format!("{}:{}", ics::COLOR_ID_PROP, ics_escape(color_id)));
+    }
+
+    lines.push("END:VEVENT".to_string());
+    lines.push("END:VCALENDAR".to_string());
+    Ok(lines.join("\r\n") + "\r\n")
+}
@@ -0,0 +1,394 @@
+//! Parsing and fetching of ICS/iCal feeds so they can be used as a `Sync` source
+//! alongside Google calendars.
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use bytes::Bytes;
+use chrono::{DateTime, NaiveDate, TimeZone, Utc};
+use eyre::{bail, eyre, Result, WrapErr};
+use google_apis_common::Connector;
+use google_calendar3::api::{Event, EventDateTime};
+use http_body_util::{BodyExt, Empty};
+use hyper_util::client::legacy::Client;
+use serde::{Deserialize, Serialize};
+
+use crate::backend::{CalendarBackend, ListOptions};
+use crate::SRC_ID_KEY;
+
+/// Returns true if `src` looks like something this module knows how to fetch:
+/// an `http(s)://` URL or a path to a local `.ics` file.
+pub fn is_ics_source(src: &str) -> bool {
+    src.starts_with("http://") || src.starts_with("https://") || src.ends_with(".ics")
+}
+
+/// Cached conditional-GET validators for a single feed, keyed by source URL/path
+/// in [`CacheFile`] and persisted next to the auth token file.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct FeedCache {
+    etag: Option<String>,
+    last_modified: Option<String>,
+    /// Last successfully-fetched body, reused verbatim on a 304.
+    body: String,
+}
+
+type CacheFile = HashMap<String, FeedCache>;
+
+fn cache_path_for(token_path: &str) -> PathBuf {
+    PathBuf::from(format!("{}.ics-cache.json", token_path))
+}
+
+fn load_cache(path: &Path) -> CacheFile {
+    std::fs::read(path)
+        .ok()
+        .and_then(|bytes| serde_json::from_slice(&bytes).ok())
+        .unwrap_or_default()
+}
+
+fn save_cache(path: &Path, cache: &CacheFile) -> Result<()> {
+    let serialized = serde_json::to_string_pretty(cache).wrap_err("Failed to serialize ICS cache")?;
+    std::fs::write(path, serialized).wrap_err(format!("Failed to write ICS cache: {}", path.display()))
+}
+
+/// Fetches and parses the VEVENTs for `src`, which may be an `http(s)://` URL or
+/// a local file path. HTTP sources are fetched with a conditional GET against
+/// the validators cached from the previous run at `token_path`'s sibling cache
+/// file; an unchanged feed (304) is not downloaded again and the cached body is
+/// reused, but that body is still parsed in full on every call.
+pub async fn fetch_events<C>(
+    client: &Client<C, Empty<Bytes>>, token_path: &str, src: &str,
+) -> Result<Vec<Event>>
+where
+    C: Connector,
+{
+    let body = if src.starts_with("http://") || src.starts_with("https://") {
+        fetch_http(client, token_path, src).await?
+    } else {
+        std::fs::read_to_string(src).wrap_err(format!("Failed to read ICS file: {}", src))?
+    };
+
+    parse_vevents(&body)
+}
+
+async fn fetch_http<C>(
+    client: &Client<C, Empty<Bytes>>, token_path: &str, src: &str,
+) -> Result<String>
+where
+    C: Connector,
+{
+    let cache_path = cache_path_for(token_path);
+    let mut cache = load_cache(&cache_path);
+    let cached = cache.get(src);
+
+    let mut req = http::Request::builder().method("GET").uri(src);
+    if let Some(cached) = cached {
+        if let Some(etag) = &cached.etag {
+            req = req.header("If-None-Match", etag);
+        }
+        if let Some(last_modified) = &cached.last_modified {
+            req = req.header("If-Modified-Since", last_modified);
+        }
+    }
+    let req = req.body(Empty::new()).wrap_err("Failed to build ICS request")?;
+
+    let response = client
+        .request(req)
+        .await
+        .wrap_err(format!("Failed to fetch ICS feed: {}", src))?;
+
+    if response.status() == http::StatusCode::NOT_MODIFIED {
+        let cached = cached.ok_or(eyre!("Got 304 for {} with no cached body", src))?;
+        log::info!("ICS feed unchanged, reusing cached body: {}", src);
+        return Ok(cached.body.clone());
+    }
+    if !response.status().is_success() {
+        bail!("Failed to fetch ICS feed {}: {}", src, response.status());
+    }
+
+    let etag = response
+        .headers()
+        .get(http::header::ETAG)
+        .and_then(|v| v.to_str().ok())
+        .map(str::to_string);
+    let last_modified = response
+        .headers()
+        .get(http::header::LAST_MODIFIED)
+        .and_then(|v| v.to_str().ok())
+        .map(str::to_string);
+
+    let bytes = response
+        .into_body()
+        .collect()
+        .await
+        .wrap_err(format!("Failed to read ICS feed body: {}", src))?
+        .to_bytes();
+    let body = String::from_utf8(bytes.to_vec())
+        .wrap_err(format!("ICS feed was not valid UTF-8: {}", src))?;
+
+    cache.insert(
+        src.to_string(),
+        FeedCache { etag, last_modified, body: body.clone() },
+    );
+    save_cache(&cache_path, &cache)?;
+
+    Ok(body)
+}
+
+/// A read-only [`CalendarBackend`] over a single ICS feed, for using one as a
+/// `Sync` `--src`. Feeds have no write API, so the mutating methods
+/// just fail; `Sync` never calls them for a source.
+pub struct IcsBackend<C> {
+    client: Client<C, Empty<Bytes>>,
+    token_path: String,
+    url: String,
+}
+
+impl<C> IcsBackend<C> {
+    pub fn new(client: Client<C, Empty<Bytes>>, token_path: String, url: String) -> Self {
+        Self { client, token_path, url }
+    }
+}
+
+#[async_trait::async_trait]
+impl<C> CalendarBackend for IcsBackend<C>
+where
+    C: Connector,
+{
+    async fn list_events(&self, _calendar: &str, _opts: &ListOptions) -> Result<(Vec<Event>, Option<String>, bool)> {
+        let events = fetch_events(&self.client, &self.token_path, &self.url)
+            .await
+            .wrap_err(format!("Failed to fetch ICS source: {}", self.url))?;
+        Ok((events, None, true))
+    }
+
+    async fn insert_event(&self, _calendar: &str, _event: Event) -> Result<Event> {
+        bail!("ICS feeds are read-only and cannot be used as a Sync --dst")
+    }
+
+    async fn patch_event(&self, _calendar: &str, _event_id: &str, _event: Event) -> Result<Event> {
+        bail!("ICS feeds are read-only and cannot be used as a Sync --dst")
+    }
+
+    async fn delete_event(&self, _calendar: &str, _event_id: &str) -> Result<()> {
+        bail!("ICS feeds are read-only and cannot be used as a Sync --dst")
+    }
+
+    async fn list_calendars(&self) -> Result<Vec<String>> {
+        bail!("ICS feeds don't have a concept of multiple calendars")
+    }
+
+    fn src_id_of(&self, event: &Event) -> Option<String> {
+        event
+            .extended_properties
+            .as_ref()
+            .and_then(|x| x.shared.as_ref())
+            .and_then(|m| m.get(SRC_ID_KEY).cloned())
+    }
+}
+
+/// Unfolds ICS line-folding (a leading space or tab continues the previous
+/// line) per RFC 5545 section 3.1.
+fn unfold(ics: &str) -> Vec<String> {
+    let mut lines: Vec<String> = Vec::new();
+    for raw in ics.split("\r\n").flat_map(|l| l.split('\n')) {
+        if (raw.starts_with(' ') || raw.starts_with('\t')) && !lines.is_empty() {
+            let last = lines.last_mut().unwrap();
+            last.push_str(&raw[1..]);
+        } else if !raw.is_empty() {
+            lines.push(raw.to_string());
+        }
+    }
+    lines
+}
+
+struct Prop {
+    params: HashMap<String, String>,
+    value: String,
+}
+
+fn parse_line(line: &str) -> Option<(String, Prop)> {
+    let colon = line.find(':')?;
+    let (head, value) = (&line[..colon], &line[colon + 1..]);
+    let mut parts = head.split(';');
+    let name = parts.next()?.to_uppercase();
+    let mut params = HashMap::new();
+    for part in parts {
+        if let Some((k, v)) = part.split_once('=') {
+            params.insert(k.to_uppercase(), v.to_string());
+        }
+    }
+    Some((name, Prop { params, value: value.to_string() }))
+}
+
+/// Parses a `DTSTART`/`DTEND`-shaped value into an [`EventDateTime`], detecting
+/// an all-day (`VALUE=DATE`) value from a timed one.
+fn parse_event_date_time(prop: &Prop) -> Result<EventDateTime> {
+    if prop.params.get("VALUE").map(String::as_str) == Some("DATE") {
+        let date = NaiveDate::parse_from_str(&prop.value, "%Y%m%d")
+            .wrap_err(format!("Invalid DATE value: {}", prop.value))?;
+        return Ok(EventDateTime {
+            date: Some(date),
+            ..Default::default()
+        });
+    }
+
+    let value = &prop.value;
+    let date_time = if let Some(stripped) = value.strip_suffix('Z') {
+        Utc.datetime_from_str(stripped, "%Y%m%dT%H%M%S")
+            .wrap_err(format!("Invalid UTC DATE-TIME value: {}", value))?
+    } else {
+        // A floating or TZID-qualified local time; without a timezone database
+        // on hand we treat it as UTC, matching this binary's other best-effort
+        // time handling.
+        Utc.datetime_from_str(value, "%Y%m%dT%H%M%S")
+            .wrap_err(format!("Invalid DATE-TIME value: {}", value))?
+    };
+    Ok(EventDateTime {
+        date_time: Some(date_time),
+        ..Default::default()
+    })
+}
+
+/// Parses every `VEVENT` block in `ics` into an [`Event`], mapping `UID` to the
+/// `SRC_ID_KEY` extended property the same way `Sync` tags its own copies.
+pub fn parse_vevents(ics: &str) -> Result<Vec<Event>> {
+    let lines = unfold(ics);
+    let mut events = Vec::new();
+    let mut current: Option<HashMap<String, Prop>> = None;
+    // RRULE/RDATE/EXDATE are collected as raw lines rather than folded into
+    // `current`, since a VEVENT can repeat RDATE/EXDATE and the HashMap above
+    // only keeps one Prop per name.
+    let mut recurrence: Vec<String> = Vec::new();
+
+    for line in &lines {
+        match line.as_str() {
+            "BEGIN:VEVENT" => {
+                current = Some(HashMap::new());
+                recurrence = Vec::new();
+            }
+            "END:VEVENT" => {
+                if let Some(props) = current.take() {
+                    events.push(props_to_event(props, recurrence.clone())?);
+                }
+            }
+            _ => {
+                if current.is_some() {
+                    let name = line.split([':', ';']).next().unwrap_or("").to_uppercase();
+                    if matches!(name.as_str(), "RRULE" | "RDATE" | "EXDATE") {
+                        recurrence.push(line.clone());
+                    } else if let Some((name, prop)) = parse_line(line) {
+                        current.as_mut().unwrap().insert(name, prop);
+                    }
+                }
+            }
+        }
+    }
+
+    Ok(events)
+}
+
+/// The `X-`-prefixed property `render_vevent` stashes `Event::color_id` under,
+/// since `color_id` (a Google Calendar concept, not an RFC 5545 one) has no
+/// standard iCal property to round-trip through.
+pub const COLOR_ID_PROP: &str = "X-EVENT-SYNC-COLOR-ID";
+
+fn props_to_event(props: HashMap<String, Prop>, recurrence: Vec<String>) -> Result<Event> {
+    let uid = props
+        .get("UID")
+        .ok_or(eyre!("VEVENT missing UID"))?
+        .value
+        .clone();
+    // Undo the TEXT-value escaping (`\\`, `\,`, `\;`, `\n`) that
+    // `caldav::render_vevent` and other iCal writers apply.
+    let text = |p: &Prop| {
+        let (mut out, mut chars) = (String::new(), p.value.chars());
+        while let Some(c) = chars.next() {
+            match (c, if c == '\\' { chars.next() } else { None }) {
+                ('\\', Some('n' | 'N')) => out.push('\n'),
+                ('\\', Some(escaped)) => out.push(escaped),
+                (c, _) => out.push(c),
+            }
+        }
+        out
+    };
+    let summary = props.get("SUMMARY").map(text);
+    let location = props.get("LOCATION").map(text);
+    let start = props
+        .get("DTSTART")
+        .map(parse_event_date_time)
+        .transpose()?;
+    let end = props.get("DTEND").map(parse_event_date_time).transpose()?;
+    let color_id = props.get(COLOR_ID_PROP).map(text);
+    let recurrence = if recurrence.is_empty() { None } else { Some(recurrence) };
+
+    let mut shared = HashMap::new();
+    shared.insert(SRC_ID_KEY.to_string(), uid.clone());
+
+    Ok(Event {
+        id: Some(uid),
+        summary,
+        location,
+        start,
+        end,
+        recurrence,
+        color_id,
+        extended_properties: Some(google_calendar3::api::EventExtendedProperties {
+            shared: Some(shared),
+            private: None,
+        }),
+        ..Default::default()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_vevents_unfolds_continued_lines() {
+        let ics = "BEGIN:VEVENT\r\nUID:1\r\nSUMMARY:A long summary that wraps\r\n onto a continuat\r\n ion line\r\nEND:VEVENT\r\n";
+        let events = parse_vevents(ics).unwrap();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].summary.as_deref(), Some("A long summary that wraps onto a continuation line"));
+    }
+
+    #[test]
+    fn parse_vevents_distinguishes_date_from_date_time() {
+        let ics = "BEGIN:VEVENT\r\nUID:1\r\nDTSTART;VALUE=DATE:20240115\r\nDTEND;VALUE=DATE:20240116\r\nEND:VEVENT\r\n\
+                   BEGIN:VEVENT\r\nUID:2\r\nDTSTART:20240115T090000Z\r\nDTEND:20240115T100000Z\r\nEND:VEVENT\r\n";
+        let events = parse_vevents(ics).unwrap();
+        assert_eq!(events.len(), 2);
+
+        let all_day = &events[0];
+        let start = all_day.start.as_ref().unwrap();
+        assert_eq!(start.date, NaiveDate::from_ymd_opt(2024, 1, 15));
+        assert!(start.date_time.is_none());
+
+        let timed = &events[1];
+        let start = timed.start.as_ref().unwrap();
+        assert!(start.date.is_none());
+        assert_eq!(start.date_time.unwrap(), Utc.with_ymd_and_hms(2024, 1, 15, 9, 0, 0).unwrap());
+    }
+
+    #[test]
+    fn parse_vevents_rejects_missing_uid() {
+        let ics = "BEGIN:VEVENT\r\nSUMMARY:No UID here\r\nEND:VEVENT\r\n";
+        assert!(parse_vevents(ics).is_err());
+    }
+
+    #[test]
+    fn parse_vevents_collects_repeated_rdate_and_exdate_lines() {
+        let ics = "BEGIN:VEVENT\r\nUID:1\r\nDTSTART:20240101T000000Z\r\n\
+                   RRULE:FREQ=DAILY;COUNT=5\r\nRDATE:20240201T000000Z\r\nEXDATE:20240102T000000Z\r\nEND:VEVENT\r\n";
+        let events = parse_vevents(ics).unwrap();
+        let recurrence = events[0].recurrence.clone().unwrap();
+        assert_eq!(
+            recurrence,
+            vec![
+                "RRULE:FREQ=DAILY;COUNT=5".to_string(),
+                "RDATE:20240201T000000Z".to_string(),
+                "EXDATE:20240102T000000Z".to_string(),
+            ]
+        );
+    }
+}
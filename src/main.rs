@@ -1,11 +1,11 @@
 use std::collections::HashMap;
 
-use chrono::{DateTime, FixedOffset, Utc};
-use eyre::{eyre, Report, Result, WrapErr};
+use chrono::{DateTime, FixedOffset, TimeZone, Utc};
+use eyre::{bail, eyre, Report, Result, WrapErr};
 use futures::stream::{Stream, StreamExt, TryStreamExt};
 use google_apis_common::Connector;
 use google_calendar3::{api::{Event, EventDateTime, EventListCall}, CalendarHub};
-use hyper_util::{client::legacy::Client, rt::TokioExecutor};
+use hyper_util::{client::legacy::{connect::HttpConnector, Client}, rt::TokioExecutor};
 use log::LevelFilter;
 use structopt::StructOpt;
 use yup_oauth2::{
@@ -13,7 +13,17 @@ use yup_oauth2::{
     NoninteractiveAuthenticator,
 };
 
+mod backend;
+mod caldav;
+mod ics;
 mod page_iterator;
+mod recurrence;
+mod sync_state;
+mod webhook;
+
+use backend::CalendarBackend;
+
+type HttpsConnector = hyper_rustls::HttpsConnector<HttpConnector>;
 
 #[derive(Debug, StructOpt)]
 struct Auth {
@@ -104,6 +114,63 @@ where
     }, |items| items)
 }
 
+/// Like [`list_events`], but drives an incremental sync: when `sync_token` is
+/// `Some`, only events changed (including deletions, flagged with
+/// `status == "cancelled"`) since that token was issued are returned. The
+/// returned [`page_iterator::SyncTokenHandle`] yields the token to persist for
+/// the *next* call once the stream has been fully drained.
+fn list_events_with_token<'a, C>(
+    hub: &'a CalendarHub<C>, calendar: &'a str,
+    updated_after: Option<DateTime<Utc>>,
+    time_min: Option<DateTime<Utc>>,
+    sync_token: Option<String>,
+) -> (impl 'a + Stream<Item = Result<Event, Report>>, page_iterator::SyncTokenHandle)
+where
+    C: Connector,
+{
+    page_iterator::stream_items_with_token(
+        move |next_page_token| {
+            let sync_token = sync_token.clone();
+            async move {
+                let req = hub.events().list(calendar);
+                let req = match next_page_token {
+                    Some(token) => req.page_token(token.as_str()),
+                    None => match &sync_token {
+                        Some(token) => req.show_deleted(true).sync_token(token.as_str()),
+                        None => {
+                            let req = maybe_update_req(req, updated_after, |req, v| req.updated_min(v));
+                            maybe_update_req(req, time_min, |req, v| req.time_min(v))
+                        }
+                    },
+                };
+                let (_body, response) = req.doit().await?;
+
+                Ok::<_, Report>((
+                    response.items.unwrap_or(vec![]),
+                    response.next_page_token,
+                    response.next_sync_token,
+                ))
+            }
+        },
+        |items| items,
+    )
+}
+
+/// Returns true if `err` is the "410 Gone" Google Calendar returns when a
+/// sync token has expired, meaning the caller must fall back to a full
+/// resync.
+fn is_sync_token_expired(err: &Report) -> bool {
+    match err.downcast_ref::<google_calendar3::Error>() {
+        Some(google_calendar3::Error::Failure(response)) => {
+            response.status() == http::StatusCode::GONE
+        }
+        Some(google_calendar3::Error::BadRequest(value)) => {
+            value.get("error").and_then(|e| e.get("code")).and_then(|c| c.as_u64()) == Some(410)
+        }
+        _ => false,
+    }
+}
+
 impl List {
     async fn run<C>(&self, hub: CalendarHub<C>) -> Result<()> where C: Connector {
         let iter = list_events(&hub, self.calendar.as_str(), None, None);
@@ -152,84 +219,387 @@ struct Sync {
         help = "Don't actually create events",
     )]
     dry_run: bool,
+    #[structopt(
+        long = "expand-window",
+        help = "Materialize recurring source events as individual instances within <lookback-days>:<lookahead-days> of now, instead of copying the raw recurrence rule (recommended: 30:366)",
+    )]
+    expand_window: Option<String>,
+}
+
+fn parse_expand_window(s: &str) -> Result<(i64, i64)> {
+    let (lookback, lookahead) = s
+        .split_once(':')
+        .ok_or(eyre!("--expand-window must be <lookback-days>:<lookahead-days>, e.g. 30:366"))?;
+    Ok((
+        lookback.parse().wrap_err(format!("Invalid lookback in --expand-window: {}", lookback))?,
+        lookahead.parse().wrap_err(format!("Invalid lookahead in --expand-window: {}", lookahead))?,
+    ))
 }
 
 const SRC_ID_KEY: &'static str = "event-sync-src-id";
 
 impl Sync {
-    async fn run<'a, C>(&self, hub: CalendarHub<C>) -> Result<()> where C: Connector {
-        let hub1 = hub.clone();
-        let hub2 = hub.clone();
+    fn dst_needs_update(&self, src: &Event, dst: &Event) -> bool {
+        dst.summary != src.summary
+            || dst.location != src.location
+            || dst.start != src.start
+            || dst.end != src.end
+            || dst.recurrence != src.recurrence
+            || dst.color_id != self.colour_id
+    }
+
+    /// Builds the destination-side `Event` for `src_event`, keyed by `uid`.
+    /// A CalDAV destination uses `uid` as `Event::id` directly (its own
+    /// cross-backend identity); a Google destination leaves `id` for Google
+    /// to assign and instead stashes `uid` in the `SRC_ID_KEY` extended
+    /// property, the only way a foreign id survives a round trip through
+    /// the API.
+    fn build_dst_event(&self, src_event: &Event, uid: &str, dst_is_caldav: bool) -> Event {
+        let id = if dst_is_caldav { Some(uid.to_string()) } else { None };
+
+        let mut extended_properties = src_event.extended_properties.clone();
+        if !dst_is_caldav {
+            let props = extended_properties.get_or_insert_with(Default::default);
+            let shared = props.shared.get_or_insert_with(Default::default);
+            shared.insert(SRC_ID_KEY.to_string(), uid.to_string());
+        }
+
+        Event {
+            id,
+            summary: src_event.summary.clone(),
+            location: src_event.location.clone(),
+            start: src_event.start.clone(),
+            end: src_event.end.clone(),
+            recurrence: src_event.recurrence.clone(),
+            extended_properties,
+            color_id: self.colour_id.clone(),
+            ..Default::default()
+        }
+    }
+
+    /// Builds the `--src`/`--dst` [`CalendarBackend`] for this sync: an ICS
+    /// feed or a CalDAV collection if the corresponding spec parses as one,
+    /// otherwise Google (the `hub` this command was given). A single
+    /// implementation handles every combination, so Google-to-Google keeps
+    /// its incremental sync tokens and `--expand-window` support regardless
+    /// of what runs alongside it.
+    fn backends<C>(
+        &self, hub: CalendarHub<C>, token_path: &str,
+        http_client: &Client<HttpsConnector, http_body_util::Empty<bytes::Bytes>>,
+        caldav_client: &Client<HttpsConnector, http_body_util::Full<bytes::Bytes>>,
+    ) -> Result<(Box<dyn CalendarBackend>, Box<dyn CalendarBackend>, bool)>
+    where
+        C: Connector + 'static,
+    {
+        if ics::is_ics_source(&self.dst) {
+            bail!("--dst cannot be an ICS feed: ICS feeds are read-only and can only be used as --src");
+        }
+
+        let src_caldav = caldav::parse_caldav_target(&self.src);
+        let dst_caldav = caldav::parse_caldav_target(&self.dst);
+        let dst_is_caldav = dst_caldav.is_some();
+
+        let src_backend: Box<dyn CalendarBackend> = if ics::is_ics_source(&self.src) {
+            Box::new(ics::IcsBackend::new(http_client.clone(), token_path.to_string(), self.src.clone()))
+        } else if let Some(config) = src_caldav {
+            Box::new(caldav::CalDavBackend::new(caldav_client.clone(), config))
+        } else {
+            Box::new(backend::GoogleBackend::new(hub.clone()))
+        };
+        let dst_backend: Box<dyn CalendarBackend> = match dst_caldav {
+            Some(config) => Box::new(caldav::CalDavBackend::new(caldav_client.clone(), config)),
+            None => Box::new(backend::GoogleBackend::new(hub)),
+        };
+
+        Ok((src_backend, dst_backend, dst_is_caldav))
+    }
+
+    async fn run<C>(
+        &self, hub: CalendarHub<C>, token_path: &str,
+        http_client: &Client<HttpsConnector, http_body_util::Empty<bytes::Bytes>>,
+        caldav_client: &Client<HttpsConnector, http_body_util::Full<bytes::Bytes>>,
+    ) -> Result<()>
+    where
+        C: Connector + 'static,
+    {
+        let (src_backend, dst_backend, dst_is_caldav) = self.backends(hub, token_path, http_client, caldav_client)?;
+
         let updated_after = self.updated_after.map(|x| x.to_utc());
         let starting_after = self.starting_after.map(|x| x.to_utc());
-        let src_events = list_events(&hub1, self.src.as_str(), updated_after.clone(), starting_after.clone());
-        let dst_events = list_events(&hub2, self.dst.as_str(), updated_after.clone(), starting_after.clone());
-
-        let dst_events_by_src_id = dst_events
-            .try_filter_map(|event| async move {
-                log::debug!("{}", serde_json::to_string(&event).unwrap());
-                let src_id = event
-                    .extended_properties
-                    .as_ref()
-                    .and_then(|x| x.shared.as_ref())
-                    .and_then(|m| m.get(SRC_ID_KEY).cloned());
-
-                match src_id {
-                    None => Ok(None),
-                    Some(id) => Ok(Some((id.clone(), event))),
-                }
-            })
-            .try_collect::<HashMap<String, Event>>()
+
+        let mut tokens = sync_state::load(token_path, &self.src, &self.dst);
+
+        let (src_events, next_src_token, src_is_full_listing) = src_backend
+            .list_events(
+                &self.src,
+                &backend::ListOptions { updated_after, time_min: starting_after, sync_token: tokens.src.take() },
+            )
+            .await?;
+        tokens.src = next_src_token;
+
+        // Always list the destination in full: it's our own mirror, so
+        // there's no incremental-listing win, and an incremental listing
+        // here would make every run after the first see only what changed on
+        // the destination (normally nothing, since nothing but this tool
+        // writes there) and re-insert everything else as a duplicate.
+        let (dst_events, _, _) = dst_backend
+            .list_events(&self.dst, &backend::ListOptions { updated_after, time_min: starting_after, sync_token: None })
             .await?;
 
-        let hub = &hub;
+        let dst_events_by_src_id: HashMap<String, Event> = dst_events
+            .into_iter()
+            .filter_map(|event| dst_backend.src_id_of(&event).map(|id| (id, event)))
+            .collect();
+
+        // Series still present in the source, expanded or not, so the
+        // deletion pass below can recognize a materialized instance that's
+        // just aged out of this run's --expand-window rather than treating
+        // it as removed from the source.
+        let active_series_uids: std::collections::HashSet<String> = src_events
+            .iter()
+            .filter(|e| e.recurrence.as_ref().is_some_and(|r| !r.is_empty()))
+            .filter_map(|e| e.id.clone())
+            .collect();
+
+        let src_events = if let Some(window) = self.expand_window.as_deref() {
+            let (lookback_days, lookahead_days) = parse_expand_window(window)?;
+            let now = Utc::now();
+            src_events
+                .into_iter()
+                .map(|src_event| {
+                    if src_event.recurrence.as_ref().is_some_and(|r| !r.is_empty()) {
+                        recurrence::expand_recurring_event(&src_event, now, lookback_days, lookahead_days)
+                    } else {
+                        Ok(vec![src_event])
+                    }
+                })
+                .collect::<Result<Vec<Vec<Event>>>>()?
+                .into_iter()
+                .flatten()
+                .collect()
+        } else {
+            src_events
+        };
+
+        let mut seen_src_ids = std::collections::HashSet::new();
+        let mut dst_ids_to_delete: Vec<String> = Vec::new();
+
+        for src_event in src_events {
+            let id = src_event.id.clone().ok_or(eyre!("Event missing id"))?;
+            let cancelled = src_event.status.as_deref() == Some("cancelled");
+            let existing = dst_events_by_src_id.get(&id);
 
-        let dst_events_by_src_id = &dst_events_by_src_id;
-        src_events
-            .try_for_each(|src_event| async move {
-                // TODO: Handle updates
-                let id = src_event.id.ok_or(eyre!("Event missing id"))?;
-                let existing = dst_events_by_src_id.get(&id);
-                match existing {
-                    Some(existing) => {
-                        log::info!("Ignoring {} because a matching event already exists: {:?}", id, existing.id);
-                        Ok(())
+            if cancelled {
+                if let Some(existing) = existing {
+                    if let Some(dst_id) = existing.id.clone() {
+                        dst_ids_to_delete.push(dst_id);
                     }
-                    None => {
-                        let mut properties = src_event.extended_properties.clone();
-                        let props = properties.get_or_insert_with(|| Default::default());
-                        let shared = props.shared.get_or_insert_with(|| Default::default());
-                        shared.insert(SRC_ID_KEY.to_string(), id.clone());
-
-                        let dst_event = Event {
-                            summary: src_event.summary.clone(),
-                            location: src_event.location.clone(),
-                            start: src_event.start.clone(),
-                            end: src_event.end.clone(),
-                            extended_properties: properties,
-                            color_id: self.colour_id.clone(),
-
-                            ..Default::default()
-                        };
-
-                        log::info!("Inserting event for {}: {}", &id, serde_json::to_string(&dst_event).unwrap());
-                        if !self.dry_run {
-                            hub.events()
-                                .insert(dst_event, &self.dst)
-                                .add_scope(google_calendar3::api::Scope::Event)
-                                .doit()
-                                .await?;
-                        }
+                }
+                continue;
+            }
+            seen_src_ids.insert(id.clone());
 
-                        Ok(())
+            match existing {
+                Some(existing) if !self.dst_needs_update(&src_event, existing) => {
+                    log::info!("Ignoring {} because the matching event is up to date: {:?}", id, existing.id);
+                }
+                Some(existing) => {
+                    let dst_id = existing.id.clone().ok_or(eyre!("Destination event missing id"))?;
+                    let dst_event = self.build_dst_event(&src_event, &id, dst_is_caldav);
+                    log::info!("Updating event for {}: {}", &id, serde_json::to_string(&dst_event).unwrap());
+                    if !self.dry_run {
+                        dst_backend.patch_event(&self.dst, &dst_id, dst_event).await?;
                     }
                 }
-            })
-            .await?;
+                None => {
+                    let dst_event = self.build_dst_event(&src_event, &id, dst_is_caldav);
+                    log::info!("Inserting event for {}: {}", &id, serde_json::to_string(&dst_event).unwrap());
+                    if !self.dry_run {
+                        dst_backend.insert_event(&self.dst, dst_event).await?;
+                    }
+                }
+            }
+        }
+
+        if src_is_full_listing {
+            for (src_id, dst_event) in dst_events_by_src_id.iter() {
+                if seen_src_ids.contains(src_id) {
+                    continue;
+                }
+                // A materialized instance of a series that's still present
+                // in the source just fell outside this run's
+                // --expand-window; it wasn't actually removed upstream.
+                if active_series_uids.iter().any(|uid| recurrence::is_instance_of(src_id, uid)) {
+                    continue;
+                }
+                if let Some(dst_id) = dst_event.id.clone() {
+                    dst_ids_to_delete.push(dst_id);
+                }
+            }
+        }
+
+        for dst_id in dst_ids_to_delete {
+            log::info!("Deleting destination event {} because it no longer exists in the source", dst_id);
+            if !self.dry_run {
+                dst_backend.delete_event(&self.dst, &dst_id).await?;
+            }
+        }
+
+        if !self.dry_run {
+            sync_state::save(token_path, &self.src, &self.dst, &tokens)?;
+        }
 
         Ok(())
     }
 }
+
+#[derive(Debug, StructOpt)]
+struct Watch {
+    #[structopt(flatten)]
+    sync: Sync,
+    #[structopt(
+        long = "webhook-url",
+        help = "Publicly reachable URL that Google will POST change notifications to",
+    )]
+    webhook_url: String,
+    #[structopt(
+        long = "listen-addr",
+        help = "Address for the notification listener to bind",
+        default_value = "0.0.0.0:8080",
+    )]
+    listen_addr: String,
+}
+
+/// How long before a channel's `expiration` to renew it.
+fn channel_renewal_margin() -> chrono::Duration {
+    chrono::Duration::hours(1)
+}
+
+fn channel_expiration(channel: &google_calendar3::api::Channel) -> Option<DateTime<Utc>> {
+    channel
+        .expiration
+        .as_ref()
+        .and_then(|s| s.parse::<i64>().ok())
+        .and_then(|ms| Utc.timestamp_millis_opt(ms).single())
+}
+
+/// Generates an unguessable channel token for Google to echo back on every
+/// push notification, so the webhook listener (bound to a publicly
+/// reachable address) can tell a real notification from a forged one. The
+/// channel id isn't suitable for this: it's generated by us and merely
+/// echoed back, not a secret. Uses `RandomState`'s self-seeded hasher keys
+/// as a source of OS randomness rather than pulling in a `rand` dependency.
+fn random_channel_token() -> String {
+    use std::hash::{BuildHasher, Hasher};
+    let a = std::collections::hash_map::RandomState::new().build_hasher().finish();
+    let b = std::collections::hash_map::RandomState::new().build_hasher().finish();
+    format!("{:016x}{:016x}", a, b)
+}
+
+impl Watch {
+    async fn watch_channel<C>(&self, hub: &CalendarHub<C>, token: &str) -> Result<google_calendar3::api::Channel>
+    where
+        C: Connector,
+    {
+        let channel = google_calendar3::api::Channel {
+            id: Some(format!("event-sync-{}", Utc::now().timestamp_nanos_opt().unwrap_or_default())),
+            type_: Some("web_hook".to_string()),
+            address: Some(self.webhook_url.clone()),
+            token: Some(token.to_string()),
+            ..Default::default()
+        };
+        let (_, channel) = hub
+            .events()
+            .watch(channel, &self.sync.src)
+            .add_scope(google_calendar3::api::Scope::Event)
+            .doit()
+            .await
+            .wrap_err("Failed to create watch channel")?;
+        Ok(channel)
+    }
+
+    async fn stop_channel<C>(&self, hub: &CalendarHub<C>, channel: &google_calendar3::api::Channel)
+    where
+        C: Connector,
+    {
+        let stop = google_calendar3::api::Channel {
+            id: channel.id.clone(),
+            resource_id: channel.resource_id.clone(),
+            ..Default::default()
+        };
+        if let Err(err) = hub.channels().stop(stop).doit().await {
+            log::warn!("Failed to stop watch channel {:?}: {}", channel.id, err);
+        }
+    }
+
+    async fn run<C>(
+        &self, hub: CalendarHub<C>, token_path: &str,
+        http_client: &Client<HttpsConnector, http_body_util::Empty<bytes::Bytes>>,
+        caldav_client: &Client<HttpsConnector, http_body_util::Full<bytes::Bytes>>,
+    ) -> Result<()>
+    where
+        C: Connector + 'static,
+    {
+        if ics::is_ics_source(&self.sync.src) {
+            bail!("Watch requires a Google Calendar source, not an ICS feed");
+        }
+
+        // Bring the mirror up to date before we start waiting on notifications.
+        self.sync.run(hub.clone(), token_path, http_client, caldav_client).await?;
+
+        let token = random_channel_token();
+        let mut channel = self.watch_channel(&hub, &token).await?;
+        let channel_id = channel.id.clone().ok_or(eyre!("Watch channel missing id"))?;
+        let channel_id_handle = webhook::ChannelIdHandle::new(channel_id);
+
+        let (notify_tx, mut notify_rx) = tokio::sync::mpsc::channel(16);
+        let listener = tokio::spawn(webhook::listen(
+            self.listen_addr.clone(),
+            channel_id_handle.clone(),
+            token.clone(),
+            notify_tx,
+        ));
+
+        let result = loop {
+            let renew_at = channel_expiration(&channel)
+                .map(|exp| exp - channel_renewal_margin())
+                .unwrap_or_else(|| Utc::now() + channel_renewal_margin());
+            let sleep_for = (renew_at - Utc::now()).to_std().unwrap_or(std::time::Duration::from_secs(60));
+
+            tokio::select! {
+                _ = notify_rx.recv() => {
+                    log::info!("Push notification received, syncing {} -> {}", self.sync.src, self.sync.dst);
+                    if let Err(err) = self.sync.run(hub.clone(), token_path, http_client, caldav_client).await {
+                        log::warn!("Sync triggered by push notification failed: {}", err);
+                    }
+                }
+                _ = tokio::time::sleep(sleep_for) => {
+                    log::info!("Renewing watch channel before it expires");
+                    self.stop_channel(&hub, &channel).await;
+                    channel = match self.watch_channel(&hub, &token).await {
+                        Ok(channel) => channel,
+                        Err(err) => break Err(err),
+                    };
+                    match channel.id.clone() {
+                        Some(id) => channel_id_handle.set(id),
+                        None => break Err(eyre!("Renewed watch channel missing id")),
+                    }
+                }
+                result = tokio::signal::ctrl_c() => {
+                    if let Err(err) = result {
+                        log::warn!("Failed to wait for ctrl-c: {}", err);
+                    }
+                    break Ok(());
+                }
+            }
+        };
+
+        listener.abort();
+        self.stop_channel(&hub, &channel).await;
+        result
+    }
+}
+
 #[derive(Debug, StructOpt)]
 struct ImportEvent {
     #[structopt(
@@ -287,6 +657,7 @@ enum Cmd {
     List(List),
     ListCalendars(ListCalendars),
     Sync(Sync),
+    Watch(Watch),
     ImportEvent(ImportEvent),
 }
 
@@ -317,20 +688,27 @@ async fn main() -> Result<()> {
         .await
         .wrap_err("Failed to get authenticator")?;
 
-    let https = hyper_rustls::HttpsConnectorBuilder::new()
-        .with_native_roots()
-        .unwrap()
-        .https_or_http()
-        .enable_http1()
-        .build();
-    let client = Client::builder(TokioExecutor::new()).build(https);
+    let build_https = || {
+        hyper_rustls::HttpsConnectorBuilder::new()
+            .with_native_roots()
+            .unwrap()
+            .https_or_http()
+            .enable_http1()
+            .build()
+    };
+    let client = Client::builder(TokioExecutor::new()).build(build_https());
+    let plain_http_client: Client<HttpsConnector, http_body_util::Empty<bytes::Bytes>> =
+        Client::builder(TokioExecutor::new()).build(build_https());
+    let caldav_http_client: Client<HttpsConnector, http_body_util::Full<bytes::Bytes>> =
+        Client::builder(TokioExecutor::new()).build(build_https());
 
     let hub = CalendarHub::new(client, authenticator);
 
     match args.cmd {
         Cmd::List(list) => list.run(hub).await?,
         Cmd::ListCalendars(list) => list.run(hub).await?,
-        Cmd::Sync(sync) => sync.run(hub).await?,
+        Cmd::Sync(sync) => sync.run(hub, &args.auth.token, &plain_http_client, &caldav_http_client).await?,
+        Cmd::Watch(watch) => watch.run(hub, &args.auth.token, &plain_http_client, &caldav_http_client).await?,
         Cmd::ImportEvent(import) => import.run(hub).await?,
     }
 
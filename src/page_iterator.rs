@@ -1,4 +1,5 @@
 use std::future::Future;
+use std::sync::{Arc, Mutex};
 
 use futures::{
     stream::{self, Stream, StreamExt, TryStreamExt},
@@ -49,3 +50,76 @@ where
         .map_ok(move |page| { stream::iter(to_items(page)).map(|x| Ok::<_, E>(x) )})
         .try_flatten()
 }
+
+/// Shared cell that receives the terminal `nextSyncToken` once the paged
+/// stream built by [`stream_pages_with_token`]/[`stream_items_with_token`]
+/// reaches its last page. Google only returns this token on the final page of
+/// a listing, so it can't be read until the stream has been fully drained.
+#[derive(Clone, Default)]
+pub struct SyncTokenHandle(Arc<Mutex<Option<String>>>);
+
+impl SyncTokenHandle {
+    pub fn get(&self) -> Option<String> {
+        self.0.lock().unwrap().clone()
+    }
+
+    fn set(&self, token: String) {
+        *self.0.lock().unwrap() = Some(token);
+    }
+}
+
+/// Like [`stream_pages`], but `fetch_page` may additionally return a sync
+/// token on the last page (i.e. whenever it returns `next_page_token: None`).
+/// That token is stashed in the returned [`SyncTokenHandle`], readable once
+/// the stream has been drained.
+pub fn stream_pages_with_token<'a, F, Fut, E, Page>(
+    fetch_page: F,
+) -> (impl 'a + Stream<Item = Result<Page, E>>, SyncTokenHandle)
+where
+    F: 'a + Fn(Option<String>) -> Fut + Send + Sync,
+    Fut: Future<Output = Result<(Page, Option<String>, Option<String>), E>>,
+    Page: Send + 'static
+{
+    let handle = SyncTokenHandle::default();
+    let stream = {
+        let handle = handle.clone();
+        stream::try_unfold((State::Going(None), fetch_page, handle), move |(state, fetch_page, handle)| {
+            async move {
+                match state {
+                    State::Done => Ok(None),
+                    State::Going(page_token) => {
+                        let (page, next_page_token, next_sync_token) = fetch_page(page_token).await?;
+                        let new_state = match next_page_token {
+                            Some(token) => State::Going(Some(token.clone())),
+                            None => {
+                                if let Some(sync_token) = next_sync_token {
+                                    handle.set(sync_token);
+                                }
+                                State::Done
+                            }
+                        };
+                        Ok(Some((page, (new_state, fetch_page, handle))))
+                    }
+                }
+            }
+        })
+    };
+    (stream, handle)
+}
+
+pub fn stream_items_with_token<'a, F, I, Fut, E, Page, Item>(
+    fetch_page: F,
+    to_items: I,
+) -> (impl 'a + Stream<Item = Result<Item, E>>, SyncTokenHandle)
+where
+    F: 'a + Fn(Option<String>) -> Fut + Send + Sync,
+    I: 'a + Fn(Page) -> Vec<Item> + Send + Sync,
+    Fut: Future<Output = Result<(Page, Option<String>, Option<String>), E>>,
+    Page: Send + 'static
+{
+    let (pages, handle) = stream_pages_with_token(fetch_page);
+    let items = pages
+        .map_ok(move |page| { stream::iter(to_items(page)).map(|x| Ok::<_, E>(x) )})
+        .try_flatten();
+    (items, handle)
+}
@@ -0,0 +1,332 @@
+//! Expands a Google Calendar `recurrence` field (RRULE/RDATE/EXDATE lines)
+//! into concrete instance start times, for destinations that can't accept a
+//! raw recurrence rule and need each occurrence materialized instead.
+//!
+//! This only implements the subset of RFC 5545 that shows up in calendar
+//! exports in practice: `FREQ`, `INTERVAL`, `COUNT`, `UNTIL` on `RRULE`, plus
+//! standalone `RDATE`/`EXDATE` lines. `BYDAY`/`BYMONTHDAY`/etc. selectors are
+//! not supported and are ignored (the rule falls back to its plain `FREQ`
+//! cadence).
+
+use std::collections::BTreeSet;
+
+use chrono::{DateTime, Datelike, Duration, NaiveDate, TimeZone, Utc};
+use eyre::{eyre, Result, WrapErr};
+use google_calendar3::api::{Event, EventDateTime};
+
+#[derive(Debug, Clone, Copy)]
+enum Freq {
+    Daily,
+    Weekly,
+    Monthly,
+    Yearly,
+}
+
+fn parse_freq(s: &str) -> Result<Freq> {
+    match s {
+        "DAILY" => Ok(Freq::Daily),
+        "WEEKLY" => Ok(Freq::Weekly),
+        "MONTHLY" => Ok(Freq::Monthly),
+        "YEARLY" => Ok(Freq::Yearly),
+        other => Err(eyre!("Unsupported RRULE FREQ: {}", other)),
+    }
+}
+
+fn parse_until(s: &str) -> Result<DateTime<Utc>> {
+    let stripped = s.trim_end_matches('Z');
+    if stripped.contains('T') {
+        chrono::Utc
+            .datetime_from_str(stripped, "%Y%m%dT%H%M%S")
+            .wrap_err(format!("Invalid RRULE UNTIL: {}", s))
+    } else {
+        let date = chrono::NaiveDate::parse_from_str(stripped, "%Y%m%d")
+            .wrap_err(format!("Invalid RRULE UNTIL: {}", s))?;
+        Ok(DateTime::<Utc>::from_naive_utc_and_offset(date.and_hms_opt(0, 0, 0).unwrap(), Utc))
+    }
+}
+
+fn parse_date_value(s: &str) -> Result<DateTime<Utc>> {
+    let stripped = s.trim_end_matches('Z');
+    chrono::Utc
+        .datetime_from_str(stripped, "%Y%m%dT%H%M%S")
+        .or_else(|_| {
+            let date = chrono::NaiveDate::parse_from_str(stripped, "%Y%m%d")?;
+            Ok(DateTime::<Utc>::from_naive_utc_and_offset(date.and_hms_opt(0, 0, 0).unwrap(), Utc))
+        })
+        .wrap_err(format!("Invalid date value: {}", s))
+}
+
+/// An upper bound on RRULE `INTERVAL`, well beyond anything a real calendar
+/// export would use, chosen so `advance`'s `12 * interval` month arithmetic
+/// for yearly rules can't overflow `i32`.
+const MAX_INTERVAL: u32 = 100_000;
+
+struct Rule {
+    freq: Freq,
+    interval: u32,
+    count: Option<u32>,
+    until: Option<DateTime<Utc>>,
+}
+
+fn parse_rrule(value: &str) -> Result<Rule> {
+    let mut freq = None;
+    let mut interval = 1;
+    let mut count = None;
+    let mut until = None;
+
+    for part in value.split(';') {
+        let (key, val) = part.split_once('=').ok_or(eyre!("Malformed RRULE part: {}", part))?;
+        match key {
+            "FREQ" => freq = Some(parse_freq(val)?),
+            "INTERVAL" => {
+                let parsed: u32 = val.parse().wrap_err(format!("Invalid INTERVAL: {}", val))?;
+                if parsed == 0 || parsed > MAX_INTERVAL {
+                    return Err(eyre!("RRULE INTERVAL out of range (must be 1..={}): {}", MAX_INTERVAL, val));
+                }
+                interval = parsed;
+            }
+            "COUNT" => count = Some(val.parse().wrap_err(format!("Invalid COUNT: {}", val))?),
+            "UNTIL" => until = Some(parse_until(val)?),
+            // BYDAY, BYMONTHDAY, WKST, etc. aren't supported; fall back to the
+            // plain FREQ/INTERVAL cadence rather than failing the whole sync.
+            _ => {}
+        }
+    }
+
+    Ok(Rule {
+        freq: freq.ok_or(eyre!("RRULE missing FREQ"))?,
+        interval,
+        count,
+        until,
+    })
+}
+
+fn advance(from: DateTime<Utc>, freq: Freq, interval: u32) -> DateTime<Utc> {
+    match freq {
+        Freq::Daily => from + Duration::days(interval as i64),
+        Freq::Weekly => from + Duration::weeks(interval as i64),
+        Freq::Monthly => add_months(from, interval as i32),
+        Freq::Yearly => add_months(from, 12 * interval as i32),
+    }
+}
+
+fn add_months(from: DateTime<Utc>, months: i32) -> DateTime<Utc> {
+    let total_months = from.month0() as i32 + months;
+    let year = from.year() + total_months.div_euclid(12);
+    let month0 = total_months.rem_euclid(12) as u32;
+    let day = from.day();
+    // Clamp to the last valid day of the target month (e.g. Jan 31 + 1 month -> Feb 28).
+    for d in (1..=day).rev() {
+        if let Some(date) = chrono::NaiveDate::from_ymd_opt(year, month0 + 1, d) {
+            return Utc.from_utc_datetime(&date.and_time(from.time()));
+        }
+    }
+    unreachable!("every month has at least one valid day")
+}
+
+/// Expands `recurrence` lines (as stored on an [`Event`](google_calendar3::api::Event))
+/// starting from `dtstart`, returning every instance start time that falls
+/// within `[window_start, window_end]`, after applying `RDATE`/`EXDATE` and
+/// any `COUNT`/`UNTIL` cap on the rule itself.
+pub fn expand_instances(
+    recurrence: &[String], dtstart: DateTime<Utc>, window_start: DateTime<Utc>, window_end: DateTime<Utc>,
+) -> Result<Vec<DateTime<Utc>>> {
+    let mut instances: BTreeSet<DateTime<Utc>> = BTreeSet::new();
+    let mut excluded: BTreeSet<DateTime<Utc>> = BTreeSet::new();
+    let mut had_rrule = false;
+
+    for line in recurrence {
+        let (name, value) = line.split_once(':').ok_or(eyre!("Malformed recurrence line: {}", line))?;
+        match name.split(';').next().unwrap_or(name) {
+            "RRULE" => {
+                had_rrule = true;
+                let rule = parse_rrule(value)?;
+                let mut current = dtstart;
+                let mut generated = 0u32;
+                loop {
+                    if let Some(count) = rule.count {
+                        if generated >= count {
+                            break;
+                        }
+                    }
+                    if let Some(until) = rule.until {
+                        if current > until {
+                            break;
+                        }
+                    }
+                    if current > window_end {
+                        break;
+                    }
+                    if current >= window_start {
+                        instances.insert(current);
+                    }
+                    generated += 1;
+                    current = advance(current, rule.freq, rule.interval);
+                }
+            }
+            "RDATE" => {
+                for part in value.split(',') {
+                    let at = parse_date_value(part)?;
+                    if at >= window_start && at <= window_end {
+                        instances.insert(at);
+                    }
+                }
+            }
+            "EXDATE" => {
+                for part in value.split(',') {
+                    excluded.insert(parse_date_value(part)?);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    if !had_rrule && instances.is_empty() && dtstart >= window_start && dtstart <= window_end {
+        instances.insert(dtstart);
+    }
+
+    Ok(instances.into_iter().filter(|i| !excluded.contains(i)).collect())
+}
+
+/// A stable per-instance id for dedup, derived from the series `UID` and the
+/// instance's start time so re-running the expansion reproduces the same id.
+pub fn instance_src_id(uid: &str, instance_start: DateTime<Utc>) -> String {
+    format!("{}:{}", uid, instance_start.timestamp())
+}
+
+/// Whether `instance_id` looks like an [`instance_src_id`] generated from
+/// `uid`, i.e. `{uid}:{timestamp}`. Used to recognize a destination copy as a
+/// materialized occurrence of a still-current recurring series even when
+/// that particular occurrence has aged out of the current `--expand-window`
+/// and so isn't present in this run's source listing.
+pub fn is_instance_of(instance_id: &str, uid: &str) -> bool {
+    instance_id
+        .strip_prefix(uid)
+        .and_then(|rest| rest.strip_prefix(':'))
+        .is_some_and(|ts| !ts.is_empty() && ts.trim_start_matches('-').chars().all(|c| c.is_ascii_digit()))
+}
+
+fn to_utc(edt: &EventDateTime) -> Option<DateTime<Utc>> {
+    edt.date_time
+        .or_else(|| edt.date.map(|d| DateTime::<Utc>::from_naive_utc_and_offset(d.and_hms_opt(0, 0, 0).unwrap(), Utc)))
+}
+
+fn all_day(date: NaiveDate) -> EventDateTime {
+    EventDateTime { date: Some(date), ..Default::default() }
+}
+
+/// Materializes every occurrence of a recurring `src_event` that falls within
+/// `lookback_days` before now and `lookahead_days` after it, as individual
+/// one-off `Event`s with no `recurrence` of their own. Each instance's id is
+/// derived from the series' `UID` plus its start time via
+/// [`instance_src_id`], so re-running this produces the same ids and the
+/// usual `SRC_ID_KEY` dedup in `Sync` still applies.
+///
+/// If `src_event` has no `end`, it is treated as an all-day event spanning a
+/// single day from its `start` date.
+pub fn expand_recurring_event(
+    src_event: &Event, now: DateTime<Utc>, lookback_days: i64, lookahead_days: i64,
+) -> Result<Vec<Event>> {
+    let uid = src_event.id.clone().ok_or(eyre!("Event missing id"))?;
+    let recurrence = src_event.recurrence.clone().unwrap_or_default();
+
+    let start = src_event.start.as_ref().ok_or(eyre!("Recurring event {} missing start", uid))?;
+    let dtstart = to_utc(start).ok_or(eyre!("Recurring event {} has an empty start", uid))?;
+
+    let (dtend, all_day_span) = match src_event.end.as_ref().and_then(to_utc) {
+        Some(dtend) => (dtend, false),
+        None => (dtstart + Duration::days(1), true),
+    };
+    let duration = dtend - dtstart;
+
+    let window_start = now - Duration::days(lookback_days);
+    let window_end = now + Duration::days(lookahead_days);
+
+    let instances = expand_instances(&recurrence, dtstart, window_start, window_end)?;
+
+    Ok(instances
+        .into_iter()
+        .map(|instance_start| {
+            let instance_end = instance_start + duration;
+            let (start, end) = if all_day_span {
+                (all_day(instance_start.date_naive()), all_day(instance_end.date_naive()))
+            } else {
+                (
+                    EventDateTime { date_time: Some(instance_start), ..Default::default() },
+                    EventDateTime { date_time: Some(instance_end), ..Default::default() },
+                )
+            };
+
+            Event {
+                id: Some(instance_src_id(&uid, instance_start)),
+                summary: src_event.summary.clone(),
+                location: src_event.location.clone(),
+                start: Some(start),
+                end: Some(end),
+                color_id: src_event.color_id.clone(),
+                ..Default::default()
+            }
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dt(s: &str) -> DateTime<Utc> {
+        Utc.datetime_from_str(s, "%Y%m%dT%H%M%S").unwrap()
+    }
+
+    #[test]
+    fn add_months_clamps_to_last_day_of_shorter_month() {
+        // Jan 31 + 1 month has no Feb 31, so it should clamp to Feb 28/29.
+        assert_eq!(add_months(dt("20230131T100000"), 1), dt("20230228T100000"));
+        // 2024 is a leap year.
+        assert_eq!(add_months(dt("20240131T100000"), 1), dt("20240229T100000"));
+    }
+
+    #[test]
+    fn add_months_carries_over_year_boundary() {
+        assert_eq!(add_months(dt("20231215T090000"), 2), dt("20240215T090000"));
+    }
+
+    #[test]
+    fn expand_instances_stops_at_whichever_of_count_or_until_is_stricter() {
+        // COUNT allows 10 occurrences, but UNTIL only allows 3; UNTIL should win.
+        let recurrence = vec!["RRULE:FREQ=DAILY;COUNT=10;UNTIL=20240103T000000Z".to_string()];
+        let dtstart = dt("20240101T000000");
+        let instances =
+            expand_instances(&recurrence, dtstart, dtstart, dtstart + Duration::days(30)).unwrap();
+        assert_eq!(instances, vec![dt("20240101T000000"), dt("20240102T000000"), dt("20240103T000000")]);
+    }
+
+    #[test]
+    fn expand_instances_respects_window_boundaries() {
+        let recurrence = vec!["RRULE:FREQ=WEEKLY;INTERVAL=1".to_string()];
+        let dtstart = dt("20240101T000000");
+        let window_start = dt("20240108T000000");
+        let window_end = dt("20240115T000000");
+        let instances = expand_instances(&recurrence, dtstart, window_start, window_end).unwrap();
+        // The Jan 1 and Jan 22 occurrences fall outside [window_start, window_end].
+        assert_eq!(instances, vec![dt("20240108T000000"), dt("20240115T000000")]);
+    }
+
+    #[test]
+    fn expand_instances_applies_exdate() {
+        let recurrence =
+            vec!["RRULE:FREQ=DAILY;COUNT=3".to_string(), "EXDATE:20240102T000000Z".to_string()];
+        let dtstart = dt("20240101T000000");
+        let instances =
+            expand_instances(&recurrence, dtstart, dtstart, dtstart + Duration::days(10)).unwrap();
+        assert_eq!(instances, vec![dt("20240101T000000"), dt("20240103T000000")]);
+    }
+
+    #[test]
+    fn is_instance_of_recognizes_only_matching_prefix_and_timestamp_suffix() {
+        let id = instance_src_id("series-1", dt("20240101T000000"));
+        assert!(is_instance_of(&id, "series-1"));
+        assert!(!is_instance_of(&id, "series-2"));
+        assert!(!is_instance_of("series-1-no-colon", "series-1"));
+    }
+}
@@ -0,0 +1,49 @@
+//! Persists the `nextSyncToken` Google hands back on the last page of an
+//! `events.list` call, so the next `Sync` invocation can ask for only what
+//! changed instead of re-listing the whole calendar. Only the source side is
+//! ever listed incrementally (the destination is our own mirror and is
+//! always listed in full), so only its token is persisted here.
+
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+use eyre::{Result, WrapErr};
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct SyncTokens {
+    pub src: Option<String>,
+}
+
+type StateFile = HashMap<String, SyncTokens>;
+
+fn state_path(token_path: &str) -> PathBuf {
+    PathBuf::from(format!("{}.sync-tokens.json", token_path))
+}
+
+fn key(src: &str, dst: &str) -> String {
+    format!("{}=>{}", src, dst)
+}
+
+fn read(path: &std::path::Path) -> StateFile {
+    std::fs::read(path)
+        .ok()
+        .and_then(|bytes| serde_json::from_slice(&bytes).ok())
+        .unwrap_or_default()
+}
+
+/// Loads the previously-persisted sync tokens for the (src, dst) pair, or
+/// defaults (i.e. "do a full resync") if none are on record.
+pub fn load(token_path: &str, src: &str, dst: &str) -> SyncTokens {
+    read(&state_path(token_path)).get(&key(src, dst)).cloned().unwrap_or_default()
+}
+
+/// Persists `tokens` for the (src, dst) pair, leaving any other pairs' state
+/// in the same file untouched.
+pub fn save(token_path: &str, src: &str, dst: &str, tokens: &SyncTokens) -> Result<()> {
+    let path = state_path(token_path);
+    let mut state = read(&path);
+    state.insert(key(src, dst), tokens.clone());
+    let serialized = serde_json::to_string_pretty(&state).wrap_err("Failed to serialize sync tokens")?;
+    std::fs::write(&path, serialized).wrap_err(format!("Failed to write sync tokens: {}", path.display()))
+}
@@ -0,0 +1,113 @@
+//! A minimal HTTP listener for Google Calendar push notifications, used by
+//! the `Watch` subcommand to learn about changes without polling.
+
+use std::sync::{Arc, Mutex};
+
+use bytes::Bytes;
+use eyre::{Result, WrapErr};
+use http_body_util::{BodyExt, Empty};
+use hyper::service::service_fn;
+use hyper::{Request, Response};
+use hyper_util::rt::{TokioExecutor, TokioIo};
+use hyper_util::server::conn::auto::Builder;
+use tokio::net::TcpListener;
+use tokio::sync::mpsc;
+
+/// Shared cell holding the id of the channel currently expected to send
+/// notifications. `Watch` replaces the channel (and so its id) on every
+/// renewal, but the listener is only spawned once, so it reads the current
+/// expected id through this handle on each request instead of capturing the
+/// id that happened to be current when `listen` was called.
+#[derive(Clone)]
+pub struct ChannelIdHandle(Arc<Mutex<String>>);
+
+impl ChannelIdHandle {
+    pub fn new(channel_id: String) -> Self {
+        Self(Arc::new(Mutex::new(channel_id)))
+    }
+
+    pub fn set(&self, channel_id: String) {
+        *self.0.lock().unwrap() = channel_id;
+    }
+
+    fn get(&self) -> String {
+        self.0.lock().unwrap().clone()
+    }
+}
+
+/// Binds `addr` and serves Google's push-notification webhook until the
+/// process is terminated, sending on `notify` whenever a notification for
+/// whichever channel `channel_id` currently holds, carrying the expected
+/// `channel_token`, reports a real change (i.e. anything other than the
+/// initial `sync` handshake Google sends when a channel is created).
+/// `channel_id` alone isn't authentication: it's generated by us and merely
+/// echoed back by Google, not a secret, and this listener is bound to a
+/// publicly reachable address by design. `channel_token` is the unguessable
+/// value `Watch` registered with the channel, which Google echoes back on
+/// every callback but an outside caller can't know; unlike the channel id it
+/// doesn't change across renewals.
+pub async fn listen(addr: String, channel_id: ChannelIdHandle, channel_token: String, notify: mpsc::Sender<()>) -> Result<()> {
+    let listener = TcpListener::bind(&addr).await.wrap_err(format!("Failed to bind {}", addr))?;
+    log::info!("Listening for push notifications on {}", addr);
+
+    loop {
+        let (stream, _) = listener.accept().await.wrap_err("Failed to accept connection")?;
+        let io = TokioIo::new(stream);
+        let channel_id = channel_id.clone();
+        let channel_token = channel_token.clone();
+        let notify = notify.clone();
+
+        tokio::spawn(async move {
+            let service = service_fn(move |req: Request<hyper::body::Incoming>| {
+                let channel_id = channel_id.clone();
+                let channel_token = channel_token.clone();
+                let notify = notify.clone();
+                async move { handle(req, channel_id, channel_token, notify).await }
+            });
+            if let Err(err) = Builder::new(TokioExecutor::new()).serve_connection(io, service).await {
+                log::warn!("Error serving push notification connection: {}", err);
+            }
+        });
+    }
+}
+
+async fn handle(
+    req: Request<hyper::body::Incoming>, channel_id: ChannelIdHandle, channel_token: String, notify: mpsc::Sender<()>,
+) -> std::result::Result<Response<Empty<Bytes>>, std::convert::Infallible> {
+    let got_channel_id = req
+        .headers()
+        .get("X-Goog-Channel-ID")
+        .and_then(|v| v.to_str().ok())
+        .map(str::to_string);
+    let got_channel_token = req
+        .headers()
+        .get("X-Goog-Channel-Token")
+        .and_then(|v| v.to_str().ok())
+        .map(str::to_string);
+    let resource_state = req
+        .headers()
+        .get("X-Goog-Resource-State")
+        .and_then(|v| v.to_str().ok())
+        .map(str::to_string);
+
+    // Drain the (empty) body so the connection can be reused.
+    let _ = req.into_body().collect().await;
+
+    let expected_channel_id = channel_id.get();
+    if got_channel_id.as_deref() != Some(expected_channel_id.as_str()) {
+        log::warn!("Ignoring notification for unknown channel: {:?}", got_channel_id);
+    } else if got_channel_token.as_deref() != Some(channel_token.as_str()) {
+        log::warn!("Ignoring notification for channel {} with a missing or incorrect token", expected_channel_id);
+    } else {
+        match resource_state.as_deref() {
+            Some("sync") => log::debug!("Received initial sync handshake for channel {}", expected_channel_id),
+            Some(state) => {
+                log::info!("Received {} notification for channel {}", state, expected_channel_id);
+                let _ = notify.send(()).await;
+            }
+            None => {}
+        }
+    }
+
+    Ok(Response::new(Empty::new()))
+}